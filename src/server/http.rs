@@ -1,9 +1,139 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
 const HTTP_VERSION: &'static str = "HTTP/1.1";
 
+// Below this many bytes, the gzip/brotli container overhead outweighs
+// any savings, so a buffered (non-chunked) body is sent as-is.
+const MIN_COMPRESS_LEN: usize = 256;
+
+/// An error encountered while parsing an HTTP request or building a
+/// response. The internal representation is private; callers classify
+/// an error with `is_parse`/`is_incomplete`/`is_io` and decide what
+/// status to answer with via `status_hint`, instead of matching over a
+/// frozen enum.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+}
+
+#[derive(Debug)]
+enum ErrorKind {
+    /// A malformed start line or header line.
+    Parse(String),
+    /// The stream ended before a full request or body was read.
+    Incomplete,
+    /// An invalid status code was set on a response.
+    InvalidStatus,
+    /// A response setter was called after the body was first written to.
+    AlreadySent,
+    /// Any other I/O failure on the underlying stream.
+    Io(io::Error),
+}
+
+impl Error {
+    fn parse(msg: impl Into<String>) -> Error {
+        Error {
+            kind: ErrorKind::Parse(msg.into()),
+        }
+    }
+
+    fn incomplete() -> Error {
+        Error {
+            kind: ErrorKind::Incomplete,
+        }
+    }
+
+    fn invalid_status() -> Error {
+        Error {
+            kind: ErrorKind::InvalidStatus,
+        }
+    }
+
+    fn already_sent() -> Error {
+        Error {
+            kind: ErrorKind::AlreadySent,
+        }
+    }
+
+    /// Whether this is a malformed start line or header line.
+    #[allow(dead_code)]
+    pub fn is_parse(&self) -> bool {
+        matches!(self.kind, ErrorKind::Parse(_))
+    }
+
+    /// Whether the stream ended before a full request or body was read.
+    #[allow(dead_code)]
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.kind, ErrorKind::Incomplete)
+    }
+
+    /// Whether this wraps an I/O failure unrelated to HTTP framing.
+    #[allow(dead_code)]
+    pub fn is_io(&self) -> bool {
+        matches!(self.kind, ErrorKind::Io(_))
+    }
+
+    // Used by the server to tell an idle keep-alive timeout apart from
+    // a genuinely malformed request.
+    pub(crate) fn is_would_block(&self) -> bool {
+        matches!(&self.kind, ErrorKind::Io(err) if err.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// The HTTP status the server should answer with when this error
+    /// surfaces while handling a request.
+    #[allow(dead_code)]
+    pub fn status_hint(&self) -> u32 {
+        match self.kind {
+            ErrorKind::Parse(_) => 400,
+            ErrorKind::Incomplete => 400,
+            ErrorKind::InvalidStatus => 500,
+            ErrorKind::AlreadySent => 500,
+            ErrorKind::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::Parse(msg) => write!(f, "malformed HTTP: {}", msg),
+            ErrorKind::Incomplete => write!(f, "incomplete HTTP stream"),
+            ErrorKind::InvalidStatus => write!(f, "invalid HTTP status code"),
+            ErrorKind::AlreadySent => write!(f, "response body already written"),
+            ErrorKind::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::incomplete(),
+            _ => Error {
+                kind: ErrorKind::Io(err),
+            },
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err.kind {
+            ErrorKind::Io(io_err) => io_err,
+            _ => io::Error::other(err.to_string()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Method {
     Get,
@@ -13,29 +143,261 @@ pub enum Method {
     Unknown(String),
 }
 
-#[derive(Debug)]
-pub struct Request {
+pub struct Request<'a, T: Read> {
     method: Method,
     location: String,
     headers: HashMap<String, String>,
-    body: Option<Vec<u8>>,
+    body: BodyState<'a, T>,
+}
+
+impl<'a, T: Read> fmt::Debug for Request<'a, T> {
+    // `BodyState` borrows the connection's reader, which has no useful
+    // debug output of its own, so it's left out rather than derived.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("location", &self.location)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+// How a request body is framed on the wire: a fixed byte count from
+// `Content-Length`, or `Transfer-Encoding: chunked`, whose total length
+// isn't known up front.
+enum Framing {
+    /// Bytes still owed for a fixed-size body.
+    Length(usize),
+    /// Bytes left in the chunk currently being read, and whether the
+    /// terminating zero-size chunk has been seen yet.
+    Chunked(usize, bool),
+}
+
+enum BodyState<'a, T: Read> {
+    /// No `Content-Length` (or a zero one), and not chunked.
+    None,
+    /// Not yet read; still holds the connection's reader.
+    Pending(Framing, &'a mut BufReader<T>),
+    /// Fully read into memory by `body`/`body_bytes`.
+    Buffered(Vec<u8>),
+    /// Handed off to a `Body` reader via `take_body`.
+    Taken,
+}
+
+/// A request body read directly off the connection, honoring whichever
+/// framing the request declared (`Content-Length` or chunked transfer
+/// encoding), without buffering it into memory up front. Obtained via
+/// `Request::take_body`.
+///
+/// Dropping a `Body` before it is read to completion drains the
+/// remaining bytes from the connection, so a handler that only wants
+/// the first part of an upload can simply stop reading rather than
+/// having to drain the rest itself.
+pub struct Body<'a, T: Read> {
+    buf: &'a mut BufReader<T>,
+    framing: Framing,
+}
+
+impl<'a, T: Read> Read for Body<'a, T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        match &mut self.framing {
+            Framing::Length(remaining) => {
+                if *remaining == 0 || out.is_empty() {
+                    return Ok(0);
+                }
+                let cap = out.len().min(*remaining);
+                let n = self.buf.read(&mut out[..cap])?;
+                *remaining -= n;
+                Ok(n)
+            }
+            Framing::Chunked(left_in_chunk, done) => {
+                if *done || out.is_empty() {
+                    return Ok(0);
+                }
+                if *left_in_chunk == 0 {
+                    let mut size_line = String::new();
+                    self.buf.read_line(&mut size_line)?;
+                    if size_line.is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "truncated chunked body",
+                        ));
+                    }
+                    // chunk-size may carry a `;`-separated extension, ignored
+                    let size =
+                        usize::from_str_radix(size_line.trim().split(';').next().unwrap(), 16)
+                            .map_err(|_| {
+                                io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size")
+                            })?;
+                    if size == 0 {
+                        // consume optional trailing headers up to the final empty line
+                        loop {
+                            let mut trailer = String::new();
+                            self.buf.read_line(&mut trailer)?;
+                            if trailer.trim_end().is_empty() {
+                                break;
+                            }
+                        }
+                        *done = true;
+                        return Ok(0);
+                    }
+                    *left_in_chunk = size;
+                }
+
+                let cap = out.len().min(*left_in_chunk);
+                let n = self.buf.read(&mut out[..cap])?;
+                *left_in_chunk -= n;
+                if *left_in_chunk == 0 {
+                    let mut crlf = [0; 2];
+                    self.buf.read_exact(&mut crlf)?;
+                }
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl<'a, T: Read> Drop for Body<'a, T> {
+    fn drop(&mut self) {
+        // A handler that stops reading early, or never reads at all,
+        // must not leave unread bytes on the stream, where they would
+        // corrupt the next request on a keep-alive connection.
+        let mut sink = [0; 4096];
+        while let Ok(n) = self.read(&mut sink) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Response<W: Write> {
     status: u32,
     headers: HashMap<String, String>,
     dirty: bool,
+    sent: bool,
+    keep_alive: bool,
+    chunked: bool,
+    content_encoding: Option<ContentEncoding>,
+    compressor: Option<Compressor>,
+    body: Vec<u8>,
     socket: W,
 }
 
+impl<W: Write> fmt::Debug for Response<W> {
+    // `Compressor` holds no useful debug output of its own, so it (and
+    // the raw socket) are left out rather than derived.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("dirty", &self.dirty)
+            .field("sent", &self.sent)
+            .field("keep_alive", &self.keep_alive)
+            .field("chunked", &self.chunked)
+            .field("content_encoding", &self.content_encoding)
+            .finish()
+    }
+}
+
+/// The response compression scheme negotiated from a request's
+/// `Accept-Encoding` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ContentEncoding {
+    Gzip,
+    Brotli,
+}
+
+// Encoder state for a compressed response. For a chunked response,
+// bytes written to `Response::write` are fed through this and the
+// compressed output, rather than the original bytes, is what gets
+// chunk-framed onto the wire; a buffered body is compressed in one go
+// by `Response::finish`.
+enum Compressor {
+    Gzip(GzEncoder<Vec<u8>>),
+    // Boxed: `CompressorWriter` is far larger than `GzEncoder`, and
+    // would otherwise bloat every `Compressor` to its size.
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Compressor {
+    fn new(encoding: ContentEncoding) -> Compressor {
+        match encoding {
+            ContentEncoding::Gzip => {
+                Compressor::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            ContentEncoding::Brotli => {
+                Compressor::Brotli(Box::new(CompressorWriter::new(Vec::new(), 4096, 5, 22)))
+            }
+        }
+    }
+
+    // Feed `buf` through the encoder without draining its output yet;
+    // used for a one-shot buffered body, where the compressed bytes are
+    // only read back once via `finish`.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Compressor::Gzip(enc) => enc.write_all(buf),
+            Compressor::Brotli(enc) => enc.write_all(buf),
+        }
+    }
+
+    // Feed `buf` through the encoder and drain whatever compressed
+    // bytes it has produced so far.
+    fn push(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(enc) => {
+                enc.write_all(buf)?;
+                enc.flush()?;
+                Ok(std::mem::take(enc.get_mut()))
+            }
+            Compressor::Brotli(enc) => {
+                enc.write_all(buf)?;
+                enc.flush()?;
+                Ok(std::mem::take(enc.get_mut()))
+            }
+        }
+    }
+
+    // Flush any remaining compressed bytes and the format trailer
+    // (gzip footer, brotli end-of-stream block).
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Compressor::Gzip(enc) => enc.finish(),
+            Compressor::Brotli(mut enc) => {
+                enc.flush()?;
+                Ok(enc.into_inner())
+            }
+        }
+    }
+}
+
+// Only the text-ish payloads this server actually produces are worth
+// compressing; binary formats (images, archives, fonts) are usually
+// already compressed and would just burn CPU for no gain.
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    matches!(
+        essence,
+        "text/plain"
+            | "text/html"
+            | "text/css"
+            | "text/csv"
+            | "text/javascript"
+            | "application/javascript"
+            | "application/json"
+            | "application/xml"
+            | "image/svg+xml"
+    )
+}
+
 // Return method, location
-fn parse_start_line(line: String) -> io::Result<(Method, String)> {
+fn parse_start_line(line: String) -> Result<(Method, String), Error> {
     let parts: Vec<&str> = line.split(' ').collect();
     if parts.len() < 3 {
-        return Err(Error::new(ErrorKind::InvalidData, "protocol not HTTP"));
+        return Err(Error::parse("protocol not HTTP"));
     } else if *parts.last().unwrap() != HTTP_VERSION {
-        return Err(Error::new(ErrorKind::InvalidData, "invalid HTTP version"));
+        return Err(Error::parse("invalid HTTP version"));
     }
 
     return Ok((
@@ -51,10 +413,10 @@ fn parse_start_line(line: String) -> io::Result<(Method, String)> {
 }
 
 // Parse a line and return the header
-fn parse_header_line(line: String) -> io::Result<(String, String)> {
+fn parse_header_line(line: String) -> Result<(String, String), Error> {
     let parts: Vec<&str> = line.splitn(2, ':').collect();
     if parts.len() != 2 {
-        return Err(Error::new(ErrorKind::InvalidData, "malformed HTTP header"));
+        return Err(Error::parse("malformed HTTP header"));
     }
     return Ok((parts[0].trim().to_string(), parts[1].trim().to_string()));
 }
@@ -157,22 +519,33 @@ impl Method {
     }
 }
 
-impl Request {
-    /// Read request from incoming tcp stream and
-    /// return the assembled request.
-    pub fn from_stream<T: Read>(stream: T) -> io::Result<Request> {
-        let mut req = Request {
-            method: Method::Get,
-            location: String::new(),
-            headers: HashMap::new(),
-            body: None,
-        };
+impl<'a, T: Read> Drop for Request<'a, T> {
+    fn drop(&mut self) {
+        // A handler that never touches the body at all must not leave
+        // it sitting unread on the stream, where it would corrupt the
+        // next request on a keep-alive connection.
+        let taken = std::mem::replace(&mut self.body, BodyState::Taken);
+        if let BodyState::Pending(framing, buf) = taken {
+            // `Body`'s own `Drop` does the actual draining.
+            Body { buf, framing };
+        }
+    }
+}
 
-        let mut buf = BufReader::new(stream);
+impl<'a, T: Read> Request<'a, T> {
+    /// Read request headers from a buffered stream and return the
+    /// assembled request; the body, if any, is left unread on `buf` and
+    /// framed for later consumption via `take_body`/`body`/`body_bytes`.
+    /// Takes the `BufReader` by reference so a single connection can be
+    /// parsed multiple times in a row (keep-alive).
+    pub fn from_stream(buf: &'a mut BufReader<T>) -> Result<Request<'a, T>, Error> {
+        let mut method = Method::Get;
+        let mut location = String::new();
+        let mut headers = HashMap::new();
 
         // Parse headers
         let mut started = false;
-        for line in (&mut buf).lines() {
+        for line in buf.by_ref().lines() {
             let line = line?;
 
             // parse starting line
@@ -182,9 +555,9 @@ impl Request {
                     // (this is recommend in spec)
                     continue;
                 }
-                let (method, location) = parse_start_line(line)?;
-                req.method = method;
-                req.location = location;
+                let (parsed_method, parsed_location) = parse_start_line(line)?;
+                method = parsed_method;
+                location = parsed_location;
                 started = true;
                 continue;
             }
@@ -194,27 +567,52 @@ impl Request {
                 break;
             }
             let (key, val) = parse_header_line(line)?;
-            req.headers.insert(key, val);
+            headers.insert(key, val);
+        }
+        if !started {
+            // the stream ended before a start line arrived
+            return Err(Error::incomplete());
         }
 
-        // Read body if Content-Length > 0
-        if req.headers.contains_key("Content-Length") {
-            let content_len: usize = req
-                .headers
-                .get("Content-Length")
-                .unwrap()
+        // Chunked transfer-encoding takes priority over a fixed
+        // Content-Length, per spec.
+        let chunked = headers
+            .get("Transfer-Encoding")
+            .map(|v| v.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+        let body = if chunked {
+            BodyState::Pending(Framing::Chunked(0, false), buf)
+        } else if let Some(len) = headers.get("Content-Length") {
+            let content_len: usize = len
                 .parse()
-                .or_else(|_| Err(Error::new(ErrorKind::InvalidData, "invalid content length")))?;
+                .map_err(|_| Error::parse("invalid content length"))?;
             if content_len > 0 {
-                let mut body = vec![];
-                body.resize(content_len, 0);
-                let read_len = buf.read(&mut body)?;
-                body.resize(read_len, 0);
-                req.body = Some(body);
+                BodyState::Pending(Framing::Length(content_len), buf)
+            } else {
+                BodyState::None
             }
-        }
+        } else {
+            BodyState::None
+        };
+
+        Ok(Request {
+            method,
+            location,
+            headers,
+            body,
+        })
+    }
 
-        Ok(req)
+    /// Whether the client asked this connection to be kept open for
+    /// further requests, based on the `Connection` header; without one,
+    /// HTTP/1.1's default of keeping the connection open applies.
+    #[allow(dead_code)]
+    pub fn keep_alive(&self) -> bool {
+        match self.headers.get("Connection").map(|v| v.to_lowercase()) {
+            Some(ref v) if v == "close" => false,
+            Some(ref v) if v == "keep-alive" => true,
+            _ => true, // HTTP/1.1 defaults to keep-alive
+        }
     }
 
     #[allow(dead_code)]
@@ -232,16 +630,48 @@ impl Request {
         self.headers.get(key)
     }
 
+    /// Hand off the request body as a reader that streams bytes
+    /// straight off the connection, honoring whichever framing the
+    /// request declared, instead of buffering it into memory. Handy
+    /// for streaming a large upload straight to a file or another
+    /// socket. Returns `None` if the request had no body, or if it was
+    /// already consumed (by a previous call to this, or to
+    /// `body`/`body_bytes`).
     #[allow(dead_code)]
-    pub fn body(&self) -> Option<String> {
-        self.body
-            .as_ref()
-            .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+    pub fn take_body(&mut self) -> Option<Body<'a, T>> {
+        if let BodyState::Pending(..) = self.body {
+            if let BodyState::Pending(framing, buf) =
+                std::mem::replace(&mut self.body, BodyState::Taken)
+            {
+                return Some(Body { buf, framing });
+            }
+        }
+        None
+    }
+
+    /// Read the full body into memory and return it as a lossily
+    /// decoded UTF-8 string. A convenience for small requests; large
+    /// uploads should be streamed via `take_body` instead.
+    #[allow(dead_code)]
+    pub fn body(&mut self) -> Option<String> {
+        self.body_bytes()
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string())
     }
 
+    /// Read the full body into memory, caching it so repeated calls
+    /// don't re-read the connection. A convenience for small requests;
+    /// large uploads should be streamed via `take_body` instead.
     #[allow(dead_code)]
-    pub fn body_bytes(&self) -> Option<&Vec<u8>> {
-        self.body.as_ref()
+    pub fn body_bytes(&mut self) -> Option<&Vec<u8>> {
+        if let Some(mut body) = self.take_body() {
+            let mut bytes = Vec::new();
+            body.read_to_end(&mut bytes).ok()?;
+            self.body = BodyState::Buffered(bytes);
+        }
+        match &self.body {
+            BodyState::Buffered(bytes) => Some(bytes),
+            _ => None,
+        }
     }
 }
 
@@ -252,6 +682,12 @@ impl<W: Write> Response<W> {
             status: 200,
             headers: HashMap::new(),
             dirty: false,
+            sent: false,
+            keep_alive: true,
+            chunked: false,
+            content_encoding: None,
+            compressor: None,
+            body: vec![],
             socket,
         };
         resp.headers
@@ -259,16 +695,34 @@ impl<W: Write> Response<W> {
         resp
     }
 
+    /// Whether the connection backing this response should be kept
+    /// open for further requests once it has been sent. Defaults to
+    /// `true`; callers typically forward `Request::keep_alive()` here.
+    #[allow(dead_code)]
+    pub fn keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// Opt into `Transfer-Encoding: chunked`, for handlers that want to
+    /// stream output without buffering the whole body or knowing its
+    /// length up front. Each `write` call is sent to the client as soon
+    /// as it is made; must be called before the first `write`.
+    #[allow(dead_code)]
+    pub fn chunked(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            return Err(Error::already_sent());
+        }
+        self.chunked = true;
+        Ok(())
+    }
+
     /// Set the specified status
     #[allow(dead_code)]
-    pub fn status(&mut self, status: u32) -> io::Result<()> {
+    pub fn status(&mut self, status: u32) -> Result<(), Error> {
         if status >= 600 {
-            return Err(Error::new(ErrorKind::InvalidData, "invalid status code"));
+            return Err(Error::invalid_status());
         } else if self.dirty {
-            return Err(Error::new(
-                ErrorKind::AlreadyExists,
-                "status already written to client",
-            ));
+            return Err(Error::already_sent());
         }
         self.status = status;
         Ok(())
@@ -276,16 +730,140 @@ impl<W: Write> Response<W> {
 
     /// Set the specified header
     #[allow(dead_code)]
-    pub fn header(&mut self, key: String, val: String) -> io::Result<()> {
+    pub fn header(&mut self, key: String, val: String) -> Result<(), Error> {
         if self.dirty {
-            return Err(Error::new(
-                ErrorKind::AlreadyExists,
-                "status already written to client",
-            ));
+            return Err(Error::already_sent());
         }
         self.headers.insert(key, val);
         Ok(())
     }
+
+    /// Negotiate response compression from `req`'s `Accept-Encoding`
+    /// header and, if a scheme is agreed on, set `Content-Encoding` and
+    /// transparently compress everything subsequently written to this
+    /// response. Picks whichever of `gzip`/`br` the client lists first.
+    /// A no-op if the client sent no usable `Accept-Encoding`, or if
+    /// this response's `Content-Type` is not one of the compressible
+    /// text types, since compressing an already-compressed or binary
+    /// payload wastes CPU for little or no size reduction. A buffered
+    /// (non-chunked) body shorter than `MIN_COMPRESS_LEN` is still sent
+    /// uncompressed, without `Content-Encoding`.
+    #[allow(dead_code)]
+    pub fn auto_compress<T: Read>(&mut self, req: &Request<T>) -> Result<(), Error> {
+        if self.dirty {
+            return Err(Error::already_sent());
+        }
+
+        let content_type = self.headers.get("Content-Type").cloned().unwrap_or_default();
+        if !is_compressible(&content_type) {
+            return Ok(());
+        }
+
+        let accepted = match req.header(&"Accept-Encoding".to_string()) {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let encoding = accepted
+            .split(',')
+            .map(|v| v.split(';').next().unwrap_or("").trim())
+            .find_map(|v| match v {
+                "gzip" => Some(ContentEncoding::Gzip),
+                "br" => Some(ContentEncoding::Brotli),
+                _ => None,
+            });
+
+        if let Some(encoding) = encoding {
+            self.headers.insert(
+                "Content-Encoding".to_string(),
+                match encoding {
+                    ContentEncoding::Gzip => "gzip".to_string(),
+                    ContentEncoding::Brotli => "br".to_string(),
+                },
+            );
+            self.content_encoding = Some(encoding);
+        }
+        Ok(())
+    }
+
+    // Send the status line and headers. `content_length` is `Some` for
+    // a fully-buffered response, `None` when streaming chunked.
+    fn send_head(&mut self, content_length: Option<usize>) -> io::Result<()> {
+        match content_length {
+            Some(len) => {
+                self.headers
+                    .insert("Content-Length".to_string(), len.to_string());
+            }
+            None => {
+                self.headers
+                    .insert("Transfer-Encoding".to_string(), "chunked".to_string());
+            }
+        }
+        self.headers.insert(
+            "Connection".to_string(),
+            if self.keep_alive {
+                "keep-alive".to_string()
+            } else {
+                "close".to_string()
+            },
+        );
+
+        let mut head = format!(
+            "{} {} {}\r\n",
+            HTTP_VERSION,
+            self.status,
+            status_reason(self.status)
+        );
+        for (key, val) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", key, val));
+        }
+        head.push_str("\r\n");
+        self.socket.write_all(head.as_bytes())
+    }
+
+    /// Finalize the response: for a buffered response, send the
+    /// headers (with the now-known `Content-Length`) followed by the
+    /// body; for a chunked response, send the closing `0\r\n\r\n`
+    /// terminator. Must be called exactly once, after the handler is
+    /// done writing.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.chunked {
+            if !self.sent {
+                self.send_head(None)?;
+                self.sent = true;
+            }
+            if let Some(compressor) = self.compressor.take() {
+                let tail = compressor.finish()?;
+                if !tail.is_empty() {
+                    write!(self.socket, "{:x}\r\n", tail.len())?;
+                    self.socket.write_all(&tail)?;
+                    self.socket.write_all(b"\r\n")?;
+                }
+            }
+            self.socket.write_all(b"0\r\n\r\n")?;
+        } else if !self.sent {
+            // A buffered body's full length is known up front, so
+            // compression only happens here, and only if it clears the
+            // worthwhile-to-compress threshold; otherwise the
+            // negotiated encoding is dropped and the body is sent as-is.
+            let body = match self.content_encoding {
+                Some(encoding) if self.body.len() >= MIN_COMPRESS_LEN => {
+                    let mut compressor = Compressor::new(encoding);
+                    let body = std::mem::take(&mut self.body);
+                    compressor.write_all(&body)?;
+                    compressor.finish()?
+                }
+                _ => {
+                    self.headers.remove("Content-Encoding");
+                    std::mem::take(&mut self.body)
+                }
+            };
+            let len = body.len();
+            self.send_head(Some(len))?;
+            self.socket.write_all(&body)?;
+            self.sent = true;
+        }
+        self.socket.flush()
+    }
 }
 
 impl<W: Write> Write for Response<W> {
@@ -294,24 +872,139 @@ impl<W: Write> Write for Response<W> {
     }
 
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut written = 0;
-        if !self.dirty {
-            // Send headers
-            let mut head = format!(
-                "{} {} {}\r\n",
-                HTTP_VERSION,
-                self.status,
-                status_reason(self.status)
-            );
-            for (key, val) in self.headers.iter() {
-                head.push_str(&format!("{}: {}\r\n", key, val));
+        self.dirty = true;
+        if self.chunked {
+            if !self.sent {
+                self.send_head(None)?;
+                self.sent = true;
             }
-            head.push_str("\r\n");
-            written += self.socket.write(head.as_bytes())?;
-            self.dirty = true;
+            // A chunked response has no known final length up front,
+            // so compression (when negotiated) streams through here on
+            // every write instead of waiting until `finish`.
+            let compressed;
+            let out = match self.content_encoding {
+                Some(encoding) => {
+                    let compressor = self
+                        .compressor
+                        .get_or_insert_with(|| Compressor::new(encoding));
+                    compressed = compressor.push(buf)?;
+                    &compressed[..]
+                }
+                None => buf,
+            };
+            if !out.is_empty() {
+                write!(self.socket, "{:x}\r\n", out.len())?;
+                self.socket.write_all(out)?;
+                self.socket.write_all(b"\r\n")?;
+            }
+        } else {
+            self.body.extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_chunked_body_with_trailer() {
+        let raw = b"POST / HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            4\r\n\
+            Wiki\r\n\
+            5\r\n\
+            pedia\r\n\
+            0\r\n\
+            X-Trailer: ignored\r\n\
+            \r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut req = Request::from_stream(&mut reader).unwrap();
+        assert_eq!(req.body().unwrap(), "Wikipedia");
+    }
+
+    #[test]
+    fn content_length_body_shorter_than_declared_is_not_padded() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut req = Request::from_stream(&mut reader).unwrap();
+        assert_eq!(req.body_bytes().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn dropping_body_mid_read_drains_it_for_the_next_request() {
+        let raw = b"POST /first HTTP/1.1\r\n\
+            Content-Length: 5\r\n\
+            \r\n\
+            helloGET /second HTTP/1.1\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+
+        {
+            let mut first = Request::from_stream(&mut reader).unwrap();
+            // Only peek at one byte of the body, then drop the request
+            // without reading the rest.
+            let mut body = first.take_body().unwrap();
+            let mut one = [0; 1];
+            body.read_exact(&mut one).unwrap();
+            assert_eq!(&one, b"h");
         }
-        // Send buffer
-        written += self.socket.write(buf)?;
-        Ok(written)
+
+        let second = Request::from_stream(&mut reader).unwrap();
+        assert_eq!(second.location(), "/second");
+    }
+
+    // Splits a response written to an in-memory `Vec<u8>` into its
+    // head and body, so tests can inspect negotiated headers without
+    // depending on the `HashMap`'s iteration order.
+    fn split_head_and_body(sent: &[u8]) -> (String, &[u8]) {
+        let idx = sent
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("response had no head/body separator");
+        (
+            String::from_utf8_lossy(&sent[..idx]).into_owned(),
+            &sent[idx + 4..],
+        )
+    }
+
+    #[test]
+    fn auto_compress_round_trips_a_large_body_through_gzip() {
+        let raw = b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let req = Request::from_stream(&mut reader).unwrap();
+
+        let mut resp = Response::for_stream(Vec::new());
+        resp.auto_compress(&req).unwrap();
+        let payload = vec![b'a'; MIN_COMPRESS_LEN * 2];
+        resp.write_all(&payload).unwrap();
+        resp.finish().unwrap();
+
+        let (head, body) = split_head_and_body(&resp.socket);
+        assert!(head.contains("Content-Encoding: gzip"));
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[test]
+    fn auto_compress_leaves_small_bodies_uncompressed() {
+        let raw = b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let req = Request::from_stream(&mut reader).unwrap();
+
+        let mut resp = Response::for_stream(Vec::new());
+        resp.auto_compress(&req).unwrap();
+        let payload = vec![b'a'; MIN_COMPRESS_LEN - 1];
+        resp.write_all(&payload).unwrap();
+        resp.finish().unwrap();
+
+        let (head, body) = split_head_and_body(&resp.socket);
+        assert!(!head.contains("Content-Encoding"));
+        assert_eq!(body, &payload[..]);
     }
 }
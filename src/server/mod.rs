@@ -1,54 +1,343 @@
 use std::io;
-use std::io::Write;
+use std::io::BufReader;
 use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 mod pool;
 use pool::Pool;
 mod http;
-use http::{Request, Response};
+pub use http::{Request, Response};
+
+/// How long a kept-alive connection may sit idle waiting for the next
+/// request before it is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the accept loop re-checks its limits while paused, either
+/// for backpressure or waiting on the next `accept`.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Tunable limits for `Server::serve`. Constructed via `Default` and
+/// overridden field-by-field, the same way `Pool` takes a plain size
+/// rather than a builder.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConfig {
+    /// Number of worker threads handling connections concurrently.
+    pub workers: usize,
+    /// Maximum number of connections handled at once. Once reached, the
+    /// accept loop pauses until in-flight connections drain back to
+    /// half this value, rather than queuing unbounded work onto the
+    /// pool. Zero disables the limit.
+    pub max_connections: usize,
+    /// Maximum number of new connections accepted per second; bursts
+    /// beyond this are delayed rather than dropped. Zero disables the
+    /// limit.
+    pub max_connection_rate: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            workers: 8,
+            max_connections: 256,
+            max_connection_rate: 500,
+        }
+    }
+}
+
+/// Stops a running `Server::serve` loop from another thread: new
+/// connections stop being accepted, and any already in-flight are left
+/// to drain through the pool's own shutdown before `serve` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    #[allow(dead_code)]
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    #[allow(dead_code)]
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+}
+
+// Caps how many new connections are accepted per second; excess
+// accepts are delayed rather than rejected, smoothing bursts instead of
+// letting them pile unbounded work onto the pool.
+struct RateLimiter {
+    max_per_sec: usize,
+    window_start: Instant,
+    accepted: usize,
+}
+
+impl RateLimiter {
+    fn new(max_per_sec: usize) -> RateLimiter {
+        RateLimiter {
+            max_per_sec,
+            window_start: Instant::now(),
+            accepted: 0,
+        }
+    }
+
+    // Block until accepting one more connection keeps within the
+    // configured rate, then record the accept.
+    fn throttle(&mut self) {
+        if self.max_per_sec == 0 {
+            return;
+        }
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.accepted = 0;
+        } else if self.accepted >= self.max_per_sec {
+            thread::sleep(Duration::from_secs(1) - elapsed);
+            self.window_start = Instant::now();
+            self.accepted = 0;
+        }
+        self.accepted += 1;
+    }
+}
+
+// Decrements the in-flight connection count when a scheduled job
+// finishes, however it finishes (including when the pool's
+// `catch_unwind` swallows a panicking job).
+struct ConnectionGuard<'a>(&'a Arc<AtomicUsize>);
+
+impl<'a> Drop for ConnectionGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 pub struct Server {
     addr: String,
+    config: ServerConfig,
+    shutdown: Arc<AtomicBool>,
 }
 
 impl Server {
     pub fn new(addr: String) -> Server {
-        Server { addr }
+        Server::with_config(addr, ServerConfig::default())
+    }
+
+    /// Construct a server with explicit accept-loop limits instead of
+    /// the defaults.
+    #[allow(dead_code)]
+    pub fn with_config(addr: String, config: ServerConfig) -> Server {
+        Server {
+            addr,
+            config,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A handle that can later be used to stop this server's accept
+    /// loop from another thread.
+    #[allow(dead_code)]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            flag: self.shutdown.clone(),
+        }
     }
 
     /// Block current thread and server incoming
     /// http requests.
     pub fn serve<F>(&self, handle: F) -> io::Result<()>
     where
-        F: Fn(Request, Response<TcpStream>) -> io::Result<()> + Sync + Send + Copy + 'static,
+        F: for<'r> Fn(Request<'r, TcpStream>, &mut Response<TcpStream>) -> io::Result<()>
+            + Sync
+            + Send
+            + Copy
+            + 'static,
     {
         let listener = TcpListener::bind(&self.addr)?;
-        let pool = Pool::new(8); // todo: make configurable?
+        listener.set_nonblocking(true)?;
+        let mut pool = Pool::new(self.config.workers);
+        let mut limiter = RateLimiter::new(self.config.max_connection_rate);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let low_water = self.config.max_connections / 2;
 
-        for conn in listener.incoming() {
-            let conn = conn?;
-            pool.schedule(move || {
-                let req = Request::from_stream(&conn);
-                let mut res = Response::for_stream(conn);
-
-                if let Err(error) = req {
-                    eprintln!("Invalid request: {}", error);
-                    res.status(400).unwrap();
-                    if let Err(error) = res.write(&vec![]) {
-                        eprintln!(
-                            "Further error encountered when sending error status: {}",
-                            error
-                        );
+        while !self.shutdown.load(Ordering::SeqCst) {
+            // Backpressure: once at capacity, stop accepting and wait
+            // for in-flight connections to drain to the low-water mark
+            // before resuming, instead of queuing unbounded work.
+            if self.config.max_connections > 0
+                && in_flight.load(Ordering::SeqCst) >= self.config.max_connections
+            {
+                while in_flight.load(Ordering::SeqCst) > low_water {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        pool.shutdown();
+                        return Ok(());
                     }
-                    return;
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
                 }
+            }
 
-                match handle(req.unwrap(), res) {
-                    Ok(_) => (),
-                    Err(error) => eprintln!("Error handling request: {}", error),
+            match listener.accept() {
+                Ok((conn, _addr)) => {
+                    limiter.throttle();
+                    in_flight.fetch_add(1, Ordering::SeqCst);
+                    let counter = in_flight.clone();
+                    pool.schedule(move || {
+                        let _guard = ConnectionGuard(&counter);
+                        if let Err(error) = Server::serve_connection(conn, handle) {
+                            eprintln!("Error on connection: {}", error);
+                        }
+                    });
                 }
-            });
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        pool.shutdown();
+        Ok(())
+    }
+
+    /// Serve requests on a single connection, reusing it for as many
+    /// requests as the client keeps it alive for.
+    fn serve_connection<F>(conn: TcpStream, handle: F) -> io::Result<()>
+    where
+        F: for<'r> Fn(Request<'r, TcpStream>, &mut Response<TcpStream>) -> io::Result<()>,
+    {
+        let mut reader = BufReader::new(conn.try_clone()?);
+        let mut first = true;
+
+        loop {
+            if !first {
+                // Only wait indefinitely for the very first request;
+                // afterwards an idle client should eventually be dropped.
+                conn.set_read_timeout(Some(IDLE_TIMEOUT))?;
+            }
+
+            let req = match Request::from_stream(&mut reader) {
+                Ok(req) => req,
+                Err(error) => {
+                    if !first && (error.is_would_block() || error.is_incomplete()) {
+                        // idle keep-alive connection timed out, or the client
+                        // closed it between requests; close quietly
+                        return Ok(());
+                    }
+                    eprintln!("Invalid request: {}", error);
+                    let mut res = Response::for_stream(conn.try_clone()?);
+                    res.keep_alive(false);
+                    res.status(error.status_hint()).unwrap();
+                    res.finish()?;
+                    return Ok(());
+                }
+            };
+            first = false;
+
+            let keep_alive = req.keep_alive();
+            let mut res = Response::for_stream(conn.try_clone()?);
+            res.keep_alive(keep_alive);
+
+            match handle(req, &mut res) {
+                Ok(_) => (),
+                Err(error) => eprintln!("Error handling request: {}", error),
+            }
+            res.finish()?;
+
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    // `TcpListener::bind` happens inside `serve`, on its own thread, so
+    // a freshly spawned server needs a moment before it's accepting;
+    // retry instead of sleeping a fixed, possibly-too-short amount.
+    fn connect_with_retry(addr: &str) -> TcpStream {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                return stream;
+            }
+            assert!(Instant::now() < deadline, "could not connect to {}", addr);
+            thread::sleep(Duration::from_millis(10));
         }
+    }
 
+    fn handler(req: Request<TcpStream>, res: &mut Response<TcpStream>) -> io::Result<()> {
+        if req.location() == "/slow" {
+            thread::sleep(Duration::from_millis(300));
+        }
+        res.status(200).unwrap();
         Ok(())
     }
+
+    #[test]
+    fn shutdown_handle_stops_the_accept_loop() {
+        let addr = "127.0.0.1:19080";
+        let server = Server::new(addr.to_string());
+        let handle = server.shutdown_handle();
+        let join = thread::spawn(move || server.serve(handler));
+
+        let mut stream = connect_with_retry(addr);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        handle.shutdown();
+        // If `serve` kept looping past the shutdown flag, this would
+        // hang rather than return.
+        join.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn max_connections_applies_backpressure_before_accepting_more() {
+        let addr = "127.0.0.1:19081";
+        let config = ServerConfig {
+            workers: 1,
+            max_connections: 1,
+            max_connection_rate: 0,
+        };
+        let server = Server::with_config(addr.to_string(), config);
+        let handle = server.shutdown_handle();
+        let join = thread::spawn(move || server.serve(handler));
+
+        // Occupies the single connection slot for ~300ms.
+        let mut slow = connect_with_retry(addr);
+        slow.write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut fast = connect_with_retry(addr);
+        fast.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut fast_response = String::new();
+        fast.read_to_string(&mut fast_response).unwrap();
+        let waited = started.elapsed();
+
+        assert!(fast_response.starts_with("HTTP/1.1 200"));
+        // With max_connections == 1 and low_water == 0, the accept loop
+        // can't take the fast connection until the slow one fully
+        // drains, so this should take most of the slow handler's sleep
+        // rather than returning immediately.
+        assert!(
+            waited >= Duration::from_millis(150),
+            "fast request returned after {:?}, backpressure did not hold it back",
+            waited
+        );
+
+        let mut slow_response = String::new();
+        slow.read_to_string(&mut slow_response).unwrap();
+        assert!(slow_response.starts_with("HTTP/1.1 200"));
+
+        handle.shutdown();
+        join.join().unwrap().unwrap();
+    }
 }
@@ -0,0 +1,160 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+enum Message {
+    Job(Box<dyn FnOnce() + Send + 'static>),
+    Shutdown,
+}
+
+pub struct Pool {
+    workers: Vec<Worker>,
+    chan: Sender<Message>,
+    recv: Arc<Mutex<Receiver<Message>>>,
+    live: Arc<AtomicUsize>,
+    size: usize,
+}
+
+struct Worker {
+    thread: thread::JoinHandle<()>,
+}
+
+impl Pool {
+    /// Start a new thread pool.
+    pub fn new(size: usize) -> Pool {
+        let (send, recv) = channel();
+        let recv = Arc::new(Mutex::new(recv));
+        let live = Arc::new(AtomicUsize::new(0));
+        let mut pool = Pool {
+            workers: vec![],
+            chan: send,
+            recv,
+            live,
+            size,
+        };
+
+        pool.workers.reserve(size);
+        for _ in 0..size {
+            pool.workers
+                .push(Worker::spawn(pool.recv.clone(), pool.live.clone()));
+        }
+
+        pool
+    }
+
+    /// Schedule a new job to be scheduled onto the
+    /// thread pool.
+    pub fn schedule<F: FnOnce() + Send + 'static>(&mut self, job: F) {
+        if self.size == 0 {
+            panic!("attempting to use pool that was shutdown");
+        }
+        self.respawn_dead_workers();
+        self.chan.send(Message::Job(Box::new(job))).unwrap();
+    }
+
+    /// Number of workers currently alive and able to pick up jobs. Handy
+    /// for observability dashboards; normally equal to the configured
+    /// size, since a panicking job no longer takes its worker down.
+    #[allow(dead_code)]
+    pub fn live_workers(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    // Replace any worker whose thread exited without going through
+    // `shutdown`, keeping the pool at its configured size.
+    fn respawn_dead_workers(&mut self) {
+        for worker in self.workers.iter_mut() {
+            if worker.thread.is_finished() {
+                *worker = Worker::spawn(self.recv.clone(), self.live.clone());
+            }
+        }
+    }
+
+    /// Terminate the pool after completing
+    /// all outstanding jobs.
+    pub fn shutdown(&mut self) {
+        for _ in 0..self.size {
+            self.chan.send(Message::Shutdown).unwrap();
+        }
+        while self.workers.len() > 0 {
+            self.workers.pop().unwrap().join();
+        }
+        self.size = 0;
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shutdown()
+    }
+}
+
+impl Worker {
+    fn spawn(recv: Arc<Mutex<Receiver<Message>>>, live: Arc<AtomicUsize>) -> Worker {
+        live.fetch_add(1, Ordering::SeqCst);
+        Worker {
+            thread: thread::spawn(move || {
+                let _guard = LiveGuard(&live);
+                loop {
+                    // assign to var to drop lock after assignment
+                    let msg = recv
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .recv()
+                        .unwrap();
+                    match msg {
+                        Message::Job(job) => {
+                            // A job that panics should only cost this one
+                            // job, not take down the worker (and with it,
+                            // the pool's capacity).
+                            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                eprintln!("worker job panicked: {:?}", panic);
+                            }
+                        }
+                        Message::Shutdown => return,
+                    }
+                }
+            }),
+        }
+    }
+
+    fn join(self) {
+        self.thread.join().unwrap();
+    }
+}
+
+// Decrements the live-worker count when a worker thread's loop exits,
+// whichever way it exits.
+struct LiveGuard<'a>(&'a Arc<AtomicUsize>);
+
+impl<'a> Drop for LiveGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn survives_a_panicking_job_and_keeps_serving() {
+        let mut pool = Pool::new(2);
+
+        pool.schedule(|| panic!("boom"));
+
+        let (tx, rx) = channel();
+        pool.schedule(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(1))
+            .expect("pool stopped processing jobs after a panic");
+
+        // Give the worker a moment to return to its receive loop, then
+        // confirm the panic didn't take it (or the pool's capacity) down.
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.live_workers(), 2);
+    }
+}